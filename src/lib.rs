@@ -2,14 +2,30 @@
 //!
 //! This is a macro that takes an object and lets you call methods on that object without naming it.
 //! The first argument is an expression that will be assigned to a variable in let binding. To make
-//! that binding mutable, prepend `mut` to the expression.
+//! that binding mutable, prepend `mut` to the expression. Prepending `ref` or `ref mut` instead
+//! binds a shared or mutable borrow of the expression, so the macro expands to `()` rather than
+//! moving the object back out.
 //! Calling a function that starts with a `.` will be converted into a method call using this
 //! variable.
 //!
 //! The supported forms are:
 //! - `.method(args..)`
+//! - `.method::<generics..>(args..)`
 //! - `let pat = .method(args..);`
+//! - `let pat = .method::<generics..>(args..);`
 //! - `var = .method(args..);`
+//! - `var = .method::<generics..>(args..);`
+//! - `move .method(args..). ... .method(args..);` (one or more calls, for builder methods that
+//!   consume and return `Self`; the result replaces the object)
+//! - `chain .method(args..). ... .method(args..);` (one or more calls, for methods that return a
+//!   borrow; the object is left untouched)
+//! - `.field = expr;` (writes to a field)
+//! - `let pat = .field;` (reads a field)
+//! - `.field;` (reads a field, discarding the value)
+//! - `for pat in iter { ... }`, `while cond { ... }`, `if cond { ... } else ...` (the object
+//!   stays in scope inside the block, so directives work there too)
+//! - `yield expr;` (must be the last directive; makes the macro evaluate to `expr` instead of
+//!   the object)
 //!
 //! Anything else will be evaluated unmodified as an expression.
 //!
@@ -32,48 +48,234 @@
 /// The `with` macro.
 ///
 /// See the module documentation for more details.
+///
+/// A `yield` directive must be the last one; anything after it, including a second `yield`,
+/// is a compile error rather than being silently accepted:
+/// ```compile_fail
+/// use with_macro::with;
+///
+/// let _ = with! {
+///     mut Vec::new() =>
+///         yield 1;
+///         yield 2;
+/// };
+/// ```
 #[macro_export]
 macro_rules! with {
     // mut expr => ...
     (mut $obj:expr => $($body:tt)*) => ({
         let mut obj = $obj;
-        with!(@parse obj $($body)*);
-        obj
+        with!(@splity obj [obj] [] $($body)*)
+    });
+
+    // ref mut expr => ...
+    (ref mut $obj:expr => $($body:tt)*) => ({
+        let obj = &mut $obj;
+        with!(@splity obj [()] [] $($body)*)
+    });
+
+    // ref expr => ...
+    (ref $obj:expr => $($body:tt)*) => ({
+        let obj = &$obj;
+        with!(@splity obj [()] [] $($body)*)
     });
 
     // expr => ...
     ($obj:expr => $($body:tt)*) => ({
         let obj = $obj;
-        with!(@parse obj $($body)*);
-        obj
+        with!(@splity obj [obj] [] $($body)*)
+    });
+
+    // looks for a trailing `yield expr;` directive, munching one token at a time since it can't
+    // be told apart from an ordinary expression statement without seeing the rest of the input
+    (@splity $obj:ident [$default:tt] [$($acc:tt)*] yield $yval:expr ;) => ({
+        with!(@parse $obj $($acc)*);
+        $yval
+    });
+    // `yield` must be the last directive; anything after it (including another `yield`) is a
+    // deliberate compile error instead of being munched into the accumulator
+    (@splity $obj:ident [$default:tt] [$($acc:tt)*] yield $yval:expr ; $($tail:tt)+) => {
+        compile_error!("only one trailing `yield` is allowed, and it must be the last directive")
+    };
+    (@splity $obj:ident [$default:tt] [$($acc:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@splity $obj [$default] [$($acc)* $next] $($rest)*)
+    };
+    (@splity $obj:ident [$default:tt] [$($acc:tt)*]) => ({
+        with!(@parse $obj $($acc)*);
+        $default
     });
 
     // termination rule
     (@parse $obj:ident) => ();
 
+    // chain .method(args..). ... .method(args..); (chained borrow calls, object left untouched)
+    (@parse $obj:ident chain $(. $method:ident ( $($args:expr),* ))+ ; $($tail:tt)*) => {
+        $obj $(. $method ( $($args),* ))+ ;
+        with!(@parse $obj $($tail)*)
+    };
+
+    // move .method(args..). ... .method(args..); (chained move-and-return builder calls; the
+    // explicit marker, mirroring `chain`, avoids ambiguity with plain sequential void calls like
+    // `.push(1).push(2);`, which a call-count heuristic would misparse as a reassignment)
+    (@parse $obj:ident move $(. $method:ident ( $($args:expr),* ))+ ; $($tail:tt)*) => {
+        $obj = $obj $(.$method($($args),*))+ ;
+        with!(@parse $obj $($tail)*)
+    };
+
+    // .method::<generics..>(args..)
+    (@parse $obj:ident . $method:ident :: < $($rest:tt)*) => {
+        with!(@targs $obj $method [] $($rest)*)
+    };
+    (@targs $obj:ident $method:ident [$($targs:tt)*] > ( $($args:expr),* ) $($tail:tt)*) => {
+        $obj.$method::<$($targs)*>($($args),*);
+        with!(@parse $obj $($tail)*)
+    };
+    // nested generics (e.g. `Vec<_>`) lex their closing brackets as one joint `>>` token
+    (@targs $obj:ident $method:ident [$($targs:tt)*] >> ( $($args:expr),* ) $($tail:tt)*) => {
+        $obj.$method::<$($targs)*>>($($args),*);
+        with!(@parse $obj $($tail)*)
+    };
+    (@targs $obj:ident $method:ident [$($targs:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@targs $obj $method [$($targs)* $next] $($rest)*)
+    };
+
     // .method(args..)
     (@parse $obj:ident . $method:ident ( $($args:expr),* ) $($tail:tt)*) => {
         $obj.$method($($args),*);
         with!(@parse $obj $($tail)*)
     };
 
+    // let pat = .method::<generics..>(args..);
+    (@parse $obj:ident let $var:pat = . $method:ident :: < $($rest:tt)*) => {
+        with!(@targs_let $obj $var, $method [] $($rest)*)
+    };
+    (@targs_let $obj:ident $var:pat, $method:ident [$($targs:tt)*] > ( $($args:expr),* ) ; $($tail:tt)*) => {
+        let $var = $obj.$method::<$($targs)*>($($args),*);
+        with!(@parse $obj $($tail)*)
+    };
+    // nested generics (e.g. `Vec<_>`) lex their closing brackets as one joint `>>` token
+    (@targs_let $obj:ident $var:pat, $method:ident [$($targs:tt)*] >> ( $($args:expr),* ) ; $($tail:tt)*) => {
+        let $var = $obj.$method::<$($targs)*>>($($args),*);
+        with!(@parse $obj $($tail)*)
+    };
+    (@targs_let $obj:ident $var:pat, $method:ident [$($targs:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@targs_let $obj $var, $method [$($targs)* $next] $($rest)*)
+    };
+
     // let pat = .method(args..);
     (@parse $obj:ident let $var:pat = . $method:ident ( $($args:expr),* ) ; $($tail:tt)*) => {
         let $var = $obj.$method($($args),*);
         with!(@parse $obj $($tail)*)
     };
 
+    // let pat = .field;
+    (@parse $obj:ident let $var:pat = . $field:ident ; $($tail:tt)*) => {
+        let $var = $obj.$field;
+        with!(@parse $obj $($tail)*)
+    };
+
+    // var = .method::<generics..>(args..);
+    (@parse $obj:ident $var:ident = . $method:ident :: < $($rest:tt)*) => {
+        with!(@targs_var $obj $var $method [] $($rest)*)
+    };
+    (@targs_var $obj:ident $var:ident $method:ident [$($targs:tt)*] > ( $($args:expr),* ) ; $($tail:tt)*) => {
+        $var = $obj.$method::<$($targs)*>($($args),*);
+        with!(@parse $obj $($tail)*)
+    };
+    // nested generics (e.g. `Vec<_>`) lex their closing brackets as one joint `>>` token
+    (@targs_var $obj:ident $var:ident $method:ident [$($targs:tt)*] >> ( $($args:expr),* ) ; $($tail:tt)*) => {
+        $var = $obj.$method::<$($targs)*>>($($args),*);
+        with!(@parse $obj $($tail)*)
+    };
+    (@targs_var $obj:ident $var:ident $method:ident [$($targs:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@targs_var $obj $var $method [$($targs)* $next] $($rest)*)
+    };
+
     // var = .method(args..);
     (@parse $obj:ident $var:ident = . $method:ident ( $($args:expr),* ) ; $($tail:tt)*) => {
         $var = $obj.$method($($args),*);
         with!(@parse $obj $($tail)*)
     };
 
+    // .field = expr;
+    (@parse $obj:ident . $field:ident = $val:expr ; $($tail:tt)*) => {
+        $obj.$field = $val;
+        with!(@parse $obj $($tail)*)
+    };
+
+    // .field; (bare field read)
+    (@parse $obj:ident . $field:ident ; $($tail:tt)*) => {
+        $obj.$field;
+        with!(@parse $obj $($tail)*)
+    };
+
+    // for $pat in $iter { body }
+    // the iterator expression is munched one token at a time since an `expr` fragment can't be
+    // followed by a `{`
+    (@parse $obj:ident for $pat:pat in $($rest:tt)*) => {
+        with!(@for $obj ($pat) [] $($rest)*)
+    };
+    (@for $obj:ident ($pat:pat) [$($iter:tt)*] { $($inner:tt)* } $($tail:tt)*) => {
+        for $pat in $($iter)* {
+            with!(@parse $obj $($inner)*);
+        }
+        with!(@parse $obj $($tail)*)
+    };
+    (@for $obj:ident ($pat:pat) [$($iter:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@for $obj ($pat) [$($iter)* $next] $($rest)*)
+    };
+
+    // while $cond { body } (same token-at-a-time munching as the `for` directive above)
+    (@parse $obj:ident while $($rest:tt)*) => {
+        with!(@while $obj [] $($rest)*)
+    };
+    (@while $obj:ident [$($cond:tt)*] { $($inner:tt)* } $($tail:tt)*) => {
+        while $($cond)* {
+            with!(@parse $obj $($inner)*);
+        }
+        with!(@parse $obj $($tail)*)
+    };
+    (@while $obj:ident [$($cond:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@while $obj [$($cond)* $next] $($rest)*)
+    };
+
+    // if $cond { body } $(else if $cond { body })* $(else { body })?
+    (@parse $obj:ident if $($rest:tt)*) => {
+        with!(@if_cond $obj [] $($rest)*)
+    };
+    (@if_cond $obj:ident [$($cond:tt)*] { $($then:tt)* } $($rest:tt)*) => {
+        with!(@if $obj { if $($cond)* { with!(@parse $obj $($then)*); } } $($rest)*)
+    };
+    (@if_cond $obj:ident [$($cond:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@if_cond $obj [$($cond)* $next] $($rest)*)
+    };
+
     // arbitrary expresion
     (@parse $obj:ident $exp:expr ; $($tail:tt)*) => {
         $exp;
         with!(@parse $obj $($tail)*)
-    }
+    };
+
+    // internal rules accumulating an if/else-if/else chain for the `if` directive above
+    (@if $obj:ident { $($acc:tt)* } else if $($rest:tt)*) => {
+        with!(@if_cond_else $obj { $($acc)* } [] $($rest)*)
+    };
+    (@if_cond_else $obj:ident { $($acc:tt)* } [$($cond:tt)*] { $($then:tt)* } $($rest:tt)*) => {
+        with!(@if $obj { $($acc)* else if $($cond)* { with!(@parse $obj $($then)*); } } $($rest)*)
+    };
+    (@if_cond_else $obj:ident { $($acc:tt)* } [$($cond:tt)*] $next:tt $($rest:tt)*) => {
+        with!(@if_cond_else $obj { $($acc)* } [$($cond)* $next] $($rest)*)
+    };
+
+    (@if $obj:ident { $($acc:tt)* } else { $($els:tt)* } $($tail:tt)*) => {
+        $($acc)* else { with!(@parse $obj $($els)*); }
+        with!(@parse $obj $($tail)*)
+    };
+
+    (@if $obj:ident { $($acc:tt)* } $($tail:tt)*) => {
+        $($acc)*
+        with!(@parse $obj $($tail)*)
+    };
 }
 
 #[cfg(test)]
@@ -92,16 +294,19 @@ mod tests {
             self.0.get()
         }
 
-        fn set_val(&self, val: i32) {
-            self.0.set(val)
+        fn set_val(&self, val: i32) -> &Self {
+            self.0.set(val);
+            self
         }
 
-        fn add(&self, n: i32) {
-            self.0.set(self.0.get() + n)
+        fn add(&self, n: i32) -> &Self {
+            self.0.set(self.0.get() + n);
+            self
         }
 
-        fn mul(&self, n: i32) {
-            self.0.set(self.0.get() * n)
+        fn mul(&self, n: i32) -> &Self {
+            self.0.set(self.0.get() * n);
+            self
         }
     }
 
@@ -153,4 +358,202 @@ mod tests {
 
         assert_eq!(vec, [Foo::new(3), Foo::new(13)]);
     }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Builder(Vec<i32>);
+
+    impl Builder {
+        fn new() -> Self {
+            Builder(Vec::new())
+        }
+
+        fn push(mut self, val: i32) -> Self {
+            self.0.push(val);
+            self
+        }
+
+        fn double_last(mut self) -> Self {
+            if let Some(last) = self.0.last_mut() {
+                *last *= 2;
+            }
+            self
+        }
+    }
+
+    #[test]
+    fn chained_builder() {
+        let b = with! {
+            mut Builder::new() =>
+                move .push(1).push(2).double_last();
+        };
+
+        assert_eq!(b, Builder(vec![1, 4]));
+    }
+
+    #[test]
+    fn single_move_builder() {
+        let b = with! {
+            mut Builder::new() =>
+                move .push(1);
+        };
+
+        assert_eq!(b, Builder(vec![1]));
+    }
+
+    #[test]
+    fn chained_borrow() {
+        let foo = with! {
+            Foo::new(0) =>
+                chain .set_val(10).add(5).mul(2);
+        };
+
+        assert_eq!(foo.get_val(), 30);
+    }
+
+    #[derive(Debug, PartialEq, Eq, Default)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[test]
+    fn fields() {
+        let point = with! {
+            mut Point::default() =>
+                .x = 3;
+                .y = 4;
+                let px = .x;
+                assert_eq!(px, 3);
+                .x;
+        };
+
+        assert_eq!(point, Point { x: 3, y: 4 });
+    }
+
+    struct Thing;
+
+    impl Thing {
+        fn make<T: Default>(&self) -> T {
+            T::default()
+        }
+
+        fn convert<T: From<i32>>(&self, val: i32) -> T {
+            T::from(val)
+        }
+    }
+
+    #[test]
+    fn turbofish() {
+        let n;
+        let c;
+        with! {
+            Thing =>
+                n = .make::<i32>();
+                c = .convert::<i64>(5);
+        };
+
+        assert_eq!(n, 0);
+        assert_eq!(c, 5i64);
+    }
+
+    #[test]
+    fn turbofish_nested_generic() {
+        let nested;
+        with! {
+            Thing =>
+                nested = .make::<Vec<Vec<i32>>>();
+        };
+
+        assert_eq!(nested, Vec::<Vec<i32>>::new());
+    }
+
+    #[test]
+    fn ref_mut() {
+        let mut vec = vec![1, 2];
+        with! {
+            ref mut vec =>
+                .push(3)
+                .push(4)
+        };
+
+        assert_eq!(vec, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn ref_borrow() {
+        let foo = Foo::new(10);
+        with! {
+            ref foo =>
+                .set_val(20)
+                chain .add(1).mul(2);
+        };
+
+        assert_eq!(foo.get_val(), 42);
+    }
+
+    #[test]
+    fn for_loop() {
+        let vec = with! {
+            mut Vec::new() =>
+                for i in 0..3 {
+                    .push(i)
+                }
+        };
+
+        assert_eq!(vec, [0, 1, 2]);
+    }
+
+    #[test]
+    fn while_loop() {
+        let mut i = 0;
+        let vec = with! {
+            mut Vec::new() =>
+                while i < 3 {
+                    .push(i)
+                    i += 1;
+                }
+        };
+
+        assert_eq!(vec, [0, 1, 2]);
+    }
+
+    #[test]
+    fn if_else() {
+        let vec = with! {
+            mut Vec::new() =>
+                for i in 0..4 {
+                    if i % 2 == 0 {
+                        .push(i)
+                    } else {
+                        .push(-i)
+                    }
+                }
+        };
+
+        assert_eq!(vec, [0, -1, 2, -3]);
+    }
+
+    #[test]
+    fn yield_value() {
+        let len = with! {
+            mut Vec::new() =>
+                .push(1)
+                .push(2)
+                .push(3)
+                let len = .len();
+                yield len;
+        };
+
+        assert_eq!(len, 3);
+    }
+
+    #[test]
+    fn yield_default() {
+        let foo = with! {
+            Foo::new(0) =>
+                .add(10)
+        };
+
+        assert_eq!(foo.get_val(), 10);
+    }
 }